@@ -9,7 +9,8 @@ use std::marker::PhantomData;
 use std::pin::Pin;
 use std::ptr::{self, NonNull};
 use std::rc::Rc;
-use std::task::{Context, Poll};
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
 
 use pin_project::pin_project;
 
@@ -98,6 +99,53 @@ struct Scheduler {
     /// References should not be handed out. Only call `push` / `pop` functions.
     /// Only call from the owning thread.
     queue: UnsafeCell<VecDeque<UnsendTask<Scheduler>>>,
+
+    /// Tasks notified from a thread other than the one that owns this
+    /// scheduler are pushed into this queue instead of `queue`, since the
+    /// `UnsafeCell` above may only be touched from the owning thread. Drained
+    /// into `queue` at the start of every `tick`.
+    remote_queue: Mutex<VecDeque<UnsendTask<Scheduler>>>,
+
+    /// Number of `!Send` tasks currently bound to this scheduler, maintained
+    /// by `bind` and `release_local`.
+    ///
+    /// # Safety
+    ///
+    /// Must only be accessed from the primary thread.
+    count: Cell<usize>,
+
+    /// Waker used to wake up the `LocalFuture` driving this scheduler.
+    ///
+    /// This is registered every time the `LocalFuture` is polled, and woken
+    /// whenever a task is scheduled onto either `queue` or `remote_queue`, or
+    /// when `tick` exits early with tasks still queued. This allows the set to
+    /// park instead of busy-polling when there is no work to do.
+    waker: AtomicWaker,
+}
+
+/// A single-slot waker cell, similar in spirit to `futures::task::AtomicWaker`.
+#[derive(Debug, Default)]
+struct AtomicWaker {
+    waker: Mutex<Option<Waker>>,
+}
+
+impl AtomicWaker {
+    /// Registers the given waker, replacing any waker previously registered.
+    fn register(&self, waker: &Waker) {
+        *self.waker.lock().unwrap() = Some(waker.clone());
+    }
+
+    /// Wakes the registered waker, if any, consuming the registration.
+    fn wake(&self) {
+        // Don't hold the lock across the call to `wake()`: waking a task can
+        // run arbitrary executor code, including a synchronous re-poll that
+        // re-enters `register`/`wake` on this same `AtomicWaker` on this
+        // thread, which would deadlock on the non-reentrant `Mutex`.
+        let waker = self.waker.lock().unwrap().take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
 }
 
 #[pin_project]
@@ -285,6 +333,81 @@ impl LocalSet {
         let scheduler = self.scheduler.clone();
         rt.block_on(LocalFuture { scheduler, future })
     }
+
+    /// Runs a future to completion on the local task set, driving any
+    /// `!Send` futures spawned on this set along with it.
+    ///
+    /// Unlike [`block_on`], this method is itself a future, and so it may be
+    /// `.await`ed from within an already-running task. This is useful when a
+    /// task that is not itself `!Send` nonetheless wants to host `!Send` work
+    /// on the current thread, for example inside a server handler.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use tokio::runtime::Runtime;
+    /// use std::rc::Rc;
+    /// use tokio::task::LocalSet;
+    ///
+    /// let mut rt = Runtime::new().unwrap();
+    /// let local = LocalSet::new();
+    ///
+    /// rt.block_on(async move {
+    ///     local
+    ///         .run_until(async move {
+    ///             let unsend_data = Rc::new("my unsend data...");
+    ///             tokio::task::spawn_local(async move {
+    ///                 println!("{}", unsend_data);
+    ///             })
+    ///             .await
+    ///             .unwrap();
+    ///         })
+    ///         .await;
+    /// });
+    /// ```
+    ///
+    /// [`block_on`]: #method.block_on
+    pub async fn run_until<F>(&self, future: F) -> F::Output
+    where
+        F: Future,
+    {
+        let scheduler = self.scheduler.clone();
+        LocalFuture { scheduler, future }.await
+    }
+
+    /// Returns the number of `!Send` tasks on this local task set that have
+    /// been polled at least once and have not yet completed, whether they are
+    /// currently queued or suspended. A task that has been spawned but not
+    /// yet polled for the first time is not counted.
+    pub fn len(&self) -> usize {
+        self.scheduler.len()
+    }
+
+    /// Returns `true` if [`len`] is `0`.
+    ///
+    /// [`len`]: #method.len
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Cancels every task spawned on this local task set, completing their
+    /// `JoinHandle`s with a cancellation error.
+    ///
+    /// This allows a long-running server to tear down a pool of per-connection
+    /// `!Send` tasks deterministically, rather than leaking them until the
+    /// `LocalSet` itself is dropped.
+    ///
+    /// This does not consume the `LocalSet`: after `shutdown` returns,
+    /// [`len`] is `0` and [`is_empty`] is `true`, but the set itself remains
+    /// usable. [`spawn_local`] may be called again afterward, and any tasks
+    /// spawned after `shutdown` are driven normally.
+    ///
+    /// [`len`]: #method.len
+    /// [`is_empty`]: #method.is_empty
+    /// [`spawn_local`]: #method.spawn_local
+    pub fn shutdown(&self) {
+        self.scheduler.shutdown_all();
+    }
 }
 
 impl Default for LocalSet {
@@ -301,16 +424,14 @@ impl<F: Future> Future for LocalFuture<F> {
         let scheduler = this.scheduler;
         let future = this.future;
 
+        // Record the waker so that `schedule` and `tick` can re-drive this
+        // set once there is actually work to do, rather than spinning.
+        scheduler.waker.register(cx.waker());
+
         scheduler.with(|| {
             scheduler.tick();
 
-            match future.poll(cx) {
-                Poll::Ready(v) => Poll::Ready(v),
-                Poll::Pending => {
-                    cx.waker().wake_by_ref();
-                    Poll::Pending
-                }
-            }
+            future.poll(cx)
         })
     }
 }
@@ -322,6 +443,7 @@ impl Schedule<Unsendable> for Scheduler {
         unsafe {
             (*self.tasks.get()).insert(task);
         }
+        self.count.set(self.count.get() + 1);
     }
 
     fn release(&self, _: UnsendTask<Self>) {
@@ -332,12 +454,24 @@ impl Schedule<Unsendable> for Scheduler {
         unsafe {
             (*self.tasks.get()).remove(task);
         }
+        self.count.set(self.count.get() - 1);
     }
 
     fn schedule(&self, task: UnsendTask<Self>) {
-        unsafe {
-            (*self.queue.get()).push_front(task);
+        if self.is_current() {
+            unsafe {
+                (*self.queue.get()).push_front(task);
+            }
+        } else {
+            // We're being woken from some other thread (e.g. a
+            // `spawn_blocking` completion, or a channel signaled off-thread).
+            // The local queue may only be touched from the owning thread, so
+            // hand the task off to the remote queue instead.
+            self.remote_queue.lock().unwrap().push_front(task);
         }
+        // A task just became runnable; make sure the `LocalFuture` gets
+        // polled again to pick it up.
+        self.waker.wake();
     }
 }
 
@@ -346,23 +480,56 @@ impl Scheduler {
         Self {
             tasks: UnsafeCell::new(task::OwnedList::new()),
             queue: UnsafeCell::new(VecDeque::with_capacity(64)),
+            remote_queue: Mutex::new(VecDeque::new()),
+            count: Cell::new(0),
+            waker: AtomicWaker::default(),
+        }
+    }
+
+    /// Returns the number of tasks currently bound to this scheduler.
+    fn len(&self) -> usize {
+        self.count.get()
+    }
+
+    /// Cancels every bound and queued task, and resets the task count.
+    fn shutdown_all(&self) {
+        // Drain all local tasks
+        while let Some(task) = self.next_task() {
+            task.shutdown();
+        }
+
+        // Drain any tasks that were scheduled from other threads. Collect
+        // them into an owned queue first so the lock isn't held while
+        // `task.shutdown()` runs — shutting down a task can synchronously
+        // wake another task whose waker re-enters `schedule()` on this
+        // thread, which would deadlock on the non-reentrant `Mutex`.
+        let remote: VecDeque<_> = self.remote_queue.lock().unwrap().drain(..).collect();
+        for task in remote {
+            task.shutdown();
         }
+
+        // Release owned tasks
+        unsafe {
+            (*self.tasks.get()).shutdown();
+        }
+        self.count.set(0);
     }
 
     fn with<F>(&self, f: impl FnOnce() -> F) -> F {
         struct Entered<'a> {
             current: &'a Cell<Option<NonNull<Scheduler>>>,
+            previous: Option<NonNull<Scheduler>>,
         }
 
         impl<'a> Drop for Entered<'a> {
             fn drop(&mut self) {
-                self.current.set(None);
+                self.current.set(self.previous);
             }
         }
 
         CURRENT_TASK_SET.with(|current| {
-            current.set(Some(NonNull::from(self)));
-            let _entered = Entered { current };
+            let previous = current.replace(Some(NonNull::from(self)));
+            let _entered = Entered { current, previous };
             f()
         })
     }
@@ -382,8 +549,24 @@ impl Scheduler {
         unsafe { (*self.queue.get()).pop_front() }
     }
 
+    fn has_queued_tasks(&self) -> bool {
+        unsafe { !(*self.queue.get()).is_empty() }
+    }
+
+    /// Moves any tasks scheduled from other threads onto the local queue.
+    fn drain_remote_queue(&self) {
+        let mut remote = self.remote_queue.lock().unwrap();
+        if remote.is_empty() {
+            return;
+        }
+        unsafe {
+            (*self.queue.get()).extend(remote.drain(..));
+        }
+    }
+
     fn tick(&self) {
         assert!(self.is_current());
+        self.drain_remote_queue();
         for _ in 0..MAX_TASKS_PER_TICK {
             let task = match self.next_task() {
                 Some(task) => task,
@@ -394,6 +577,13 @@ impl Scheduler {
                 self.schedule(task);
             }
         }
+
+        // We hit the per-tick task budget with tasks still queued. Wake
+        // ourselves so the remaining work is picked up on the next poll,
+        // instead of waiting indefinitely for an external wakeup.
+        if self.has_queued_tasks() {
+            self.waker.wake();
+        }
     }
 }
 
@@ -407,15 +597,7 @@ impl fmt::Debug for Scheduler {
 }
 impl Drop for Scheduler {
     fn drop(&mut self) {
-        // Drain all local tasks
-        while let Some(task) = self.next_task() {
-            task.shutdown();
-        }
-
-        // Release owned tasks
-        unsafe {
-            (*self.tasks.get()).shutdown();
-        }
+        self.shutdown_all();
     }
 }
 
@@ -582,4 +764,219 @@ mod tests {
             .unwrap();
         })
     }
+
+    #[test]
+    fn idle_local_set_does_not_spin() {
+        use futures_util::future;
+        use futures_util::task::ArcWake;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        #[derive(Default)]
+        struct WakeCounter(AtomicUsize);
+
+        impl ArcWake for WakeCounter {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let local = LocalSet::new();
+        let mut fut = Box::pin(LocalFuture {
+            scheduler: local.scheduler.clone(),
+            future: future::pending::<()>(),
+        });
+
+        let counter = Arc::new(WakeCounter::default());
+        let waker = futures_util::task::waker(Arc::clone(&counter));
+        let mut cx = Context::from_waker(&waker);
+
+        // With no tasks queued, polling an idle set must return `Pending`
+        // without waking itself, or the executor would spin on it forever.
+        assert_eq!(fut.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+
+        // A task becoming runnable must still drive a re-poll.
+        local.spawn_local(async {});
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn nested_run_until_restores_current_set() {
+        let mut rt = runtime::Runtime::new().unwrap();
+        let outer = LocalSet::new();
+        outer.block_on(&mut rt, async {
+            let inner = LocalSet::new();
+            // `inner`'s future resolves immediately, so `inner.run_until`
+            // returns within the same poll of `outer`'s `LocalFuture`.
+            inner.run_until(async {}).await;
+
+            // `inner.run_until` must not have clobbered `outer`'s entry in
+            // `CURRENT_TASK_SET`; `spawn_local` here must still resolve to
+            // `outer`.
+            spawn_local(async {}).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn remote_wakeup_drains_into_local_queue() {
+        use std::sync::{Arc, Mutex};
+        use std::task::Waker;
+        use std::thread;
+
+        struct RemoteWake {
+            state: Arc<Mutex<(bool, Option<Waker>)>>,
+        }
+
+        impl Future for RemoteWake {
+            type Output = ();
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+                let mut state = self.state.lock().unwrap();
+                if state.0 {
+                    Poll::Ready(())
+                } else {
+                    state.1 = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+
+        let mut rt = runtime::Runtime::new().unwrap();
+        let local = LocalSet::new();
+        let state = Arc::new(Mutex::new((false, None)));
+
+        local.block_on(&mut rt, async move {
+            let join = spawn_local(RemoteWake {
+                state: state.clone(),
+            });
+
+            // Yield so the set polls the task once and it registers its waker.
+            crate::time::delay_for(std::time::Duration::from_millis(10)).await;
+
+            // Wake the task from a thread other than the one that owns the
+            // `LocalSet`; this must land in the remote queue rather than
+            // corrupting the local one.
+            thread::spawn(move || {
+                let mut state = state.lock().unwrap();
+                state.0 = true;
+                if let Some(waker) = state.1.take() {
+                    waker.wake();
+                }
+            })
+            .join()
+            .unwrap();
+
+            join.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn shutdown_cancels_tasks_and_resets_len() {
+        use futures_util::future;
+
+        let mut rt = runtime::Runtime::new().unwrap();
+        let local = LocalSet::new();
+
+        assert!(local.is_empty());
+        let join = local.spawn_local(future::pending::<()>());
+        // Drive one tick so the task is polled, and thus bound, once.
+        local.block_on(&mut rt, future::ready(()));
+        assert_eq!(local.len(), 1);
+
+        local.shutdown();
+        assert!(local.is_empty());
+
+        assert!(
+            rt.block_on(join).is_err(),
+            "a cancelled task's JoinHandle should complete with an error"
+        );
+
+        // The set remains usable after `shutdown`: further tasks may still
+        // be spawned and driven normally.
+        let value =
+            local.block_on(&mut rt, async { spawn_local(async { 42 }).await.unwrap() });
+        assert_eq!(value, 42);
+    }
+
+    #[test]
+    fn len_returns_to_zero_after_normal_completion() {
+        let mut rt = runtime::Runtime::new().unwrap();
+        let local = LocalSet::new();
+
+        assert!(local.is_empty());
+        let value = local.block_on(&mut rt, async {
+            let join = spawn_local(async { 42 });
+            join.await.unwrap()
+        });
+        assert_eq!(value, 42);
+
+        // `bind`/`release_local` must balance out once the task has run to
+        // completion normally, not just when tasks are force-cancelled by
+        // `shutdown_all`.
+        assert!(local.is_empty());
+        assert_eq!(local.len(), 0);
+    }
+
+    #[test]
+    fn shutdown_all_does_not_deadlock_on_reentrant_remote_wake() {
+        // Regression test: `shutdown_all` used to hold the `remote_queue`
+        // lock for the entire `for task in self.remote_queue.lock()...drain(..)`
+        // loop. If shutting down a task in that loop synchronously wakes a
+        // sibling task that is awaiting its `JoinHandle`, that wake re-enters
+        // `Schedule::schedule`. Since `shutdown_all` (and thus `shutdown`) is
+        // called outside `scheduler.with()`, `is_current()` is false, so the
+        // sibling's wake tries to lock `remote_queue` again on the very same
+        // thread -- a deadlock on the non-reentrant `Mutex`, exactly the
+        // hazard commit a5561ea fixed for `AtomicWaker::wake()`.
+        use futures_util::future;
+        use std::sync::{Arc, Mutex};
+        use std::task::Waker;
+
+        struct RemoteWake {
+            state: Arc<Mutex<(bool, Option<Waker>)>>,
+        }
+
+        impl Future for RemoteWake {
+            type Output = ();
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+                let mut state = self.state.lock().unwrap();
+                if state.0 {
+                    Poll::Ready(())
+                } else {
+                    state.1 = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+
+        let mut rt = runtime::Runtime::new().unwrap();
+        let local = LocalSet::new();
+        let state = Arc::new(Mutex::new((false, None)));
+
+        let join_b = local.spawn_local(RemoteWake {
+            state: state.clone(),
+        });
+        // `task_a` holds `join_b`'s `JoinHandle` and parks on it.
+        local.spawn_local(async move {
+            let _ = join_b.await;
+        });
+
+        // Drive one tick so both tasks are polled once: `task_b` registers
+        // its waker in `state`, and `task_a` registers itself as a waiter on
+        // `join_b`. Both are left suspended (not queued) afterward.
+        local.block_on(&mut rt, future::ready(()));
+
+        // Wake `task_b` from outside `scheduler.with()`, just like a
+        // cross-thread completion would -- this lands it in the remote
+        // queue rather than the local one.
+        state.lock().unwrap().1.take().unwrap().wake();
+
+        // Calling `shutdown` directly (not through `block_on`/`run_until`)
+        // drains `task_b` out of the remote queue and cancels it, which
+        // synchronously wakes `task_a`. Without the fix, this hangs forever.
+        local.shutdown();
+        assert!(local.is_empty());
+    }
 }